@@ -1,40 +1,83 @@
 use holochain_wasm_utils::memory::{
-    allocation::{AllocationError, AllocationResult, Length, WasmAllocation},
+    allocation::{
+        AllocationError, AllocationResult, AllocationStats, Length, WasmAllocation,
+        WASM32_MAX_BYTES,
+    },
     stack::WasmStack,
     MemoryBits, MemoryInt,
 };
-use wasmi::{MemoryRef, ModuleRef};
+use wasmi::{
+    memory_units::{Bytes, Pages, RoundUpTo},
+    MemoryRef, ModuleRef,
+};
 
 //--------------------------------------------------------------------------------------------------
 // WASM Memory Manager
 //--------------------------------------------------------------------------------------------------
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Which wasm address space a `WasmPageManager` is indexing its memory export as.
+pub enum WasmMemoryIndexType {
+    /// The classic 32-bit wasm memory: a single allocation may carry at most
+    /// `WASM32_MAX_BYTES`.
+    Wasm32,
+    /// A memory64 module. Recognised so callers can say which kind of module they're running,
+    /// but not actually functional: the vendored `wasmi` only ever addresses memory with a
+    /// `u32` offset, so there is no 64-bit address space underneath for this to reach. Every
+    /// nonzero-length `allocate`/`write` against a `Wasm64`-indexed manager fails with
+    /// `AllocationError::Unsupported` until `wasmi` itself grows real memory64 support.
+    Wasm64,
+}
+
+impl Default for WasmMemoryIndexType {
+    fn default() -> Self {
+        WasmMemoryIndexType::Wasm32
+    }
+}
+
 #[derive(Clone, Debug)]
-/// Struct for managing a WASM Memory Instance as a single page memory stack
+/// Struct for managing a WASM Memory Instance as a memory stack that can span several pages
 pub struct WasmPageManager {
     stack: WasmStack,
     wasm_memory: MemoryRef,
+    /// Physical offset at which this manager's own address space starts. Zero for a
+    /// stand-alone `WasmPageManager`; non-zero when it is one arena of a `MemoryManager`
+    /// multiplexing several virtual memories over the same linear memory.
+    base: MemoryInt,
+    /// Which address space this manager's memory export is indexed as; governs `max_length`.
+    index_type: WasmMemoryIndexType,
 }
 
-/// A Memory Manager limited to one wasm memory page that works like a stack.
+/// A Memory Manager that works like a stack and grows the underlying wasm memory on demand.
 /// With this Memory Manager, the WASM host (i.e. the Ribosome) and WASM module (i.e. the Zome)
 /// only need to pass around an i64 to communicate any data.
-/// That u64 is the last memory allocation on the stack:
-/// it is split in an i16 'offset' in the upper bits and an i16 'length' in the lower bits.
-/// This fits with the 64KiB sized of a memory Page.
+/// That i64 is the last memory allocation on the stack:
+/// it is split in a 32-bit 'offset' in the upper bits and a 32-bit 'length' in the lower bits.
 /// Complex input arguments should be stored on the latest allocation on the stack.
 /// Complex output arguments can be stored anywhere on stack.
 /// Since zero sized allocations are not allowed,
 /// it is possible to pass around a return and/or error code with the following convention:
-/// using the i16 'offset' as return code and i16 'length' set to zero
+/// using the 32-bit 'offset' as return code and 32-bit 'length' set to zero
 /// to indicate its a return code.
 /// Return code of 0 means success, while any other value means a failure and gives the error code.
-/// In the future, to handle bigger memory needs, we could do same with an i64 instead
-/// and handle multiple memory Pages.
+/// Allocations are no longer bound to a single 64KiB page: whenever the stack top crosses into
+/// a page that hasn't been committed yet, the underlying wasm memory is grown to cover it.
+/// `WasmStack` is a freeing-bump allocator underneath, so allocations can also be freed with
+/// `deallocate` and their space reused by later allocations of a compatible size, instead of
+/// only ever growing monotonically.
+/// A manager can be built against `WasmMemoryIndexType::Wasm64` to mark it as backing a
+/// memory64 module, but that mode isn't functional yet: it only rejects every allocation, since
+/// the underlying `wasmi` memory is still addressed with a plain 32-bit offset.
 #[allow(unknown_lints)]
 #[allow(cast_lossless)]
 impl WasmPageManager {
     pub fn new(wasm_instance: &ModuleRef) -> Self {
+        Self::new_with_index_type(wasm_instance, WasmMemoryIndexType::default())
+    }
+
+    /// Like `new`, but indexing the module's memory export as `index_type` rather than the
+    /// default `Wasm32`, which governs the largest single allocation `max_length` will allow.
+    pub fn new_with_index_type(wasm_instance: &ModuleRef, index_type: WasmMemoryIndexType) -> Self {
         // get wasm memory reference from module
         let wasm_memory = wasm_instance
             .export_by_name("memory")
@@ -43,44 +86,93 @@ impl WasmPageManager {
             .expect("in module generated by rustc export named 'memory' should be a memory; qed")
             .clone();
 
-        return WasmPageManager {
+        WasmPageManager {
             stack: WasmStack::default(),
             wasm_memory,
-        };
+            base: 0,
+            index_type,
+        }
+    }
+
+    /// Build a `WasmPageManager` whose own address space starts at `base` within the shared
+    /// `wasm_memory` and may never bump past `bucket_size` bytes into it, so several of these
+    /// can be multiplexed over one linear memory without one arena's stack ever crossing into
+    /// its neighbour's bucket. Used by `MemoryManager` to hand out independent virtual memories.
+    pub(crate) fn with_base(
+        wasm_memory: MemoryRef,
+        base: MemoryInt,
+        bucket_size: MemoryInt,
+        index_type: WasmMemoryIndexType,
+    ) -> Self {
+        WasmPageManager {
+            stack: WasmStack::with_max(bucket_size),
+            wasm_memory,
+            base,
+            index_type,
+        }
+    }
+
+    /// Largest number of bytes a single allocation may carry under this manager's
+    /// `index_type`: the classic 4 GiB wasm32 ceiling, or zero for `Wasm64`, which this build
+    /// cannot back at all — so every nonzero-length request against it is rejected up front.
+    fn max_length(&self) -> MemoryBits {
+        match self.index_type {
+            WasmMemoryIndexType::Wasm32 => WASM32_MAX_BYTES,
+            WasmMemoryIndexType::Wasm64 => 0,
+        }
     }
 
     /// Allocate on stack without writing in it
     pub fn allocate(&mut self, length: Length) -> AllocationResult {
+        if MemoryInt::from(length) as MemoryBits > self.max_length() {
+            return Err(AllocationError::Unsupported);
+        }
+
         let allocation = self.stack.next_allocation(length)?;
-        let top = self.stack.allocate(allocation)?;
-        Ok(WasmAllocation::new(MemoryInt::from(top).into(), length)?)
+        let new_top = self.stack.peek_top(allocation)?;
+        // Validate/grow backing memory *before* the allocation is committed to the stack, so a
+        // failed grow can never strand allocator bookkeeping for space that was never backed.
+        self.grow_to(self.base + new_top)?;
+        self.stack.allocate(allocation)?;
+        // The allocation's own offset within the shared memory, not `new_top` (which is where
+        // the bump pointer ends up *after* this block, used above only to size the grow).
+        let physical_offset = self.base + MemoryInt::from(allocation.offset());
+        Ok(WasmAllocation::new(physical_offset.into(), length)?)
+    }
+
+    /// Grow `wasm_memory` so that the page containing physical offset `top` is committed.
+    /// Rounds `top` up to whole `Pages` and grows by however many pages are
+    /// still missing; a `grow` failure is reported as `AllocationError::OutOfBounds`
+    /// rather than allowed to panic later on an out-of-bounds `set`/`get`.
+    fn grow_to(&mut self, top: MemoryInt) -> Result<(), AllocationError> {
+        let top_pages: Pages = Bytes(top as usize).round_up_to();
+        let current_pages: Pages = self.wasm_memory.current_size();
+
+        if current_pages < top_pages {
+            self.wasm_memory
+                .grow(top_pages - current_pages)
+                .map_err(|_| AllocationError::OutOfBounds)?;
+        }
+
+        Ok(())
     }
 
     /// Write data on top of stack
     pub fn write(&mut self, data: &[u8]) -> AllocationResult {
-        if data.len() as MemoryBits > WasmAllocation::max() {
-            return Err(AllocationError::OutOfBounds);
+        if data.len() as MemoryBits > self.max_length() {
+            return Err(AllocationError::Unsupported);
         }
 
         if data.is_empty() {
             return Err(AllocationError::ZeroLength);
         }
 
-        // scope for mutable borrow of self
+        // `allocate` already grows `wasm_memory` (via `grow_to`) so that `mem_buf` is fully
+        // committed before we touch it. Freshly grown pages are zero-filled by wasmi per the
+        // wasm spec, so a zome reading the tail of a grown region never observes stale bytes,
+        // even before this `set` writes into it.
         let mem_buf = self.allocate((data.len() as MemoryInt).into())?;
 
-        // @TODO make this work when wasmi is used consistently inside/outside wasm
-        // let top_bytes = Bytes(MemoryInt::from(self.stack.top()) as usize);
-        // let top_pages: Pages = top_bytes.round_up_to();
-        // let current_pages: Pages = self.wasm_memory.current_size();
-
-        // if current_pages < top_pages {
-        //     match self.wasm_memory.grow(top_pages - current_pages) {
-        //         Ok(new_pages) => assert_eq!(new_pages, top_pages),
-        //         Err(_) => return Err(AllocationError::OutOfBounds),
-        //     }
-        // }
-
         self.wasm_memory
             .set(MemoryInt::from(mem_buf.offset()), &data)
             .expect("memory should be writable");
@@ -97,4 +189,156 @@ impl WasmPageManager {
             )
             .expect("Successfully retrieve the result")
     }
+
+    /// Free a previous allocation so its block can be reused by a later allocation of a
+    /// compatible size, instead of being stranded on the stack forever.
+    pub fn deallocate(&mut self, allocation: WasmAllocation) -> Result<(), AllocationError> {
+        // `allocate` hands out physical offsets, but the stack keys its blocks by offsets
+        // relative to this manager's `base`.
+        let offset = MemoryInt::from(allocation.offset())
+            .checked_sub(self.base)
+            .ok_or(AllocationError::BadInput)?;
+        self.stack
+            .deallocate(WasmAllocation::new(offset.into(), allocation.length())?)
+    }
+
+    /// Diagnostics on the freeing-bump allocator: bytes currently in use, the peak usage
+    /// ever observed, and the number of live allocations.
+    pub fn stats(&self) -> AllocationStats {
+        self.stack.stats()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Virtual Memory Manager
+//--------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Debug)]
+/// Multiplexes several independent `WasmPageManager` arenas over one wasm module's linear
+/// memory. A Ribosome that wants to keep e.g. input args, large output buffers and a scratch
+/// region from interleaving on a single stack can ask for a separate virtual memory per use,
+/// without any of them fragmenting or clobbering the others' allocations.
+///
+/// Each virtual memory owns a slice of the physical address space reserved up-front in
+/// `BUCKET_PAGES`-sized buckets; growing one arena only grows the shared physical memory, it
+/// never shifts another arena's bucket. Each arena's own `WasmStack` is built with its bucket
+/// size as a hard ceiling, so an arena that tries to grow past its bucket fails with
+/// `AllocationError::OutOfBounds` instead of silently bumping into the next arena's base.
+pub struct MemoryManager {
+    wasm_memory: MemoryRef,
+    virtual_memories: Vec<WasmPageManager>,
+}
+
+impl MemoryManager {
+    /// Pages reserved as the stride between two virtual memories' buckets. An arena can grow
+    /// up to this many pages before it risks colliding with its neighbour's bucket.
+    const BUCKET_PAGES: usize = 16;
+
+    /// Partition `wasm_instance`'s memory export into `num_virtual_memories` independent
+    /// arenas, each addressed from its own `BUCKET_PAGES`-page-sized bucket, indexed as
+    /// `Wasm32`.
+    pub fn new(wasm_instance: &ModuleRef, num_virtual_memories: usize) -> Self {
+        Self::new_with_index_type(wasm_instance, num_virtual_memories, WasmMemoryIndexType::default())
+    }
+
+    /// Like `new`, but indexing every arena as `index_type` rather than the default `Wasm32`.
+    pub fn new_with_index_type(
+        wasm_instance: &ModuleRef,
+        num_virtual_memories: usize,
+        index_type: WasmMemoryIndexType,
+    ) -> Self {
+        let wasm_memory = wasm_instance
+            .export_by_name("memory")
+            .expect("all modules compiled with rustc should have an export named 'memory'; qed")
+            .as_memory()
+            .expect("in module generated by rustc export named 'memory' should be a memory; qed")
+            .clone();
+
+        let bucket_bytes: Bytes = Pages(Self::BUCKET_PAGES).into();
+        let virtual_memories = (0..num_virtual_memories)
+            .map(|id| {
+                let base = (bucket_bytes.0 * id) as MemoryInt;
+                WasmPageManager::with_base(
+                    wasm_memory.clone(),
+                    base,
+                    bucket_bytes.0 as MemoryInt,
+                    index_type,
+                )
+            })
+            .collect();
+
+        MemoryManager {
+            wasm_memory,
+            virtual_memories,
+        }
+    }
+
+    /// Borrow the virtual memory `id` as a `WasmPageManager` handle: allocate, write and read
+    /// against it only ever touch that arena's own bucket of the shared physical memory.
+    pub fn virtual_memory(&mut self, id: usize) -> &mut WasmPageManager {
+        &mut self.virtual_memories[id]
+    }
+
+    /// Number of pages currently committed in the shared physical memory backing every arena.
+    pub fn current_size(&self) -> Pages {
+        self.wasm_memory.current_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmi::MemoryInstance;
+
+    fn shared_memory() -> MemoryRef {
+        MemoryInstance::alloc(Pages(1), None).expect("failed to allocate wasm memory for test")
+    }
+
+    #[test]
+    fn adjacent_arenas_stay_isolated_until_one_overflows_its_bucket() {
+        let wasm_memory = shared_memory();
+        // 100 bytes plus the 8-byte block header rounds up to the 128-byte order, so this is
+        // the smallest bucket a 100-byte allocation actually fits exactly.
+        let bucket_size: MemoryInt = 128;
+
+        let mut first =
+            WasmPageManager::with_base(wasm_memory.clone(), 0, bucket_size, WasmMemoryIndexType::default());
+        let mut second = WasmPageManager::with_base(
+            wasm_memory.clone(),
+            bucket_size,
+            bucket_size,
+            WasmMemoryIndexType::default(),
+        );
+
+        // Filling the first arena right up to its bucket still succeeds...
+        let first_alloc = first.allocate(Length::from(100)).expect("fits exactly in one bucket");
+        assert_eq!(MemoryInt::from(first_alloc.offset()), 0);
+
+        // ...and the second arena's own allocations start at its base, unaffected by how much
+        // of its own bucket the first arena has used.
+        let second_alloc = second.allocate(Length::from(10)).unwrap();
+        assert_eq!(MemoryInt::from(second_alloc.offset()), bucket_size);
+
+        // Once the first arena's bucket is full, it must fail rather than silently bump into
+        // the second arena's base.
+        assert_eq!(first.allocate(Length::from(1)), Err(AllocationError::OutOfBounds));
+    }
+
+    #[test]
+    fn wasm32_addressing_allows_any_length_the_channel_can_carry() {
+        let wasm_memory = shared_memory();
+        let wasm32 =
+            WasmPageManager::with_base(wasm_memory, 0, MemoryInt::MAX, WasmMemoryIndexType::Wasm32);
+
+        assert_eq!(wasm32.max_length(), WASM32_MAX_BYTES);
+    }
+
+    #[test]
+    fn wasm64_addressing_is_not_yet_backed_by_wasmi_and_rejects_every_allocation() {
+        let wasm_memory = shared_memory();
+        let mut wasm64 =
+            WasmPageManager::with_base(wasm_memory, 0, MemoryInt::MAX, WasmMemoryIndexType::Wasm64);
+
+        assert_eq!(wasm64.allocate(Length::from(1)), Err(AllocationError::Unsupported));
+    }
 }
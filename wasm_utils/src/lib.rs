@@ -0,0 +1,4 @@
+//! Types and allocator shared between the Ribosome host and the Zome wasm modules it runs,
+//! for describing and managing allocations within a wasm linear memory.
+
+pub mod memory;
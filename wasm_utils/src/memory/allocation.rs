@@ -0,0 +1,165 @@
+//! Vocabulary for describing a single allocation living somewhere in a wasm linear memory:
+//! the offset/length halves of the host<->module i64 channel, the errors that can come out of
+//! (de)allocating, and the `AllocationStats` diagnostics `WasmStack` reports.
+
+/// Width of the single i64 value the WASM host (the Ribosome) and the WASM module (the Zome)
+/// pass back and forth to describe an allocation: a 32-bit offset packed into the upper bits
+/// and a 32-bit length packed into the lower bits.
+pub type MemoryBits = u64;
+
+/// Width of a single offset or length half of the `MemoryBits` channel.
+///
+/// This matches `wasmi::MemoryRef::get`/`set`, which only ever take a `u32` offset: the
+/// vendored wasmi has no memory64 backing, so there is no wider address space for a larger
+/// `MemoryInt` to actually reach. A `Wasm64`-indexed memory (see
+/// `holochain_wasm_utils::memory`'s consumers) is recognised but not functional for exactly
+/// this reason — widening this type alone cannot make it one.
+pub type MemoryInt = u32;
+
+/// Byte ceiling of a `Wasm32`-indexed memory: the classic wasm32 4 GiB address space, i.e. the
+/// full width `MemoryInt` can represent.
+pub const WASM32_MAX_BYTES: MemoryBits = MemoryInt::MAX as MemoryBits;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Ways an allocation, deallocation, or the memory grow backing it can fail.
+pub enum AllocationError {
+    /// Zero-length allocations are reserved for the "this i64 is a return/error code"
+    /// convention, so they are never valid allocations.
+    ZeroLength,
+    /// The allocation, or the memory `grow` it required, doesn't fit in the addressable range.
+    OutOfBounds,
+    /// Asked to free (or otherwise operate on) an offset this stack never handed out, or to
+    /// free a block that is already free.
+    BadInput,
+    /// The memory's addressing mode can't service this request, e.g. a `Wasm64`-indexed memory,
+    /// which this crate recognises but cannot yet back with real 64-bit offsets.
+    Unsupported,
+}
+
+pub type AllocationResult = Result<WasmAllocation, AllocationError>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// The offset half of a `WasmAllocation`.
+pub struct Offset(MemoryInt);
+
+impl Offset {
+    pub fn new(i: MemoryInt) -> Self {
+        Offset(i)
+    }
+}
+
+impl From<MemoryInt> for Offset {
+    fn from(i: MemoryInt) -> Self {
+        Offset(i)
+    }
+}
+
+impl From<Offset> for MemoryInt {
+    fn from(offset: Offset) -> Self {
+        offset.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// The length half of a `WasmAllocation`.
+pub struct Length(MemoryInt);
+
+impl Length {
+    pub fn new(i: MemoryInt) -> Self {
+        Length(i)
+    }
+}
+
+impl From<MemoryInt> for Length {
+    fn from(i: MemoryInt) -> Self {
+        Length(i)
+    }
+}
+
+impl From<Length> for MemoryInt {
+    fn from(length: Length) -> Self {
+        length.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A single allocation: a 32-bit offset and a 32-bit length, the pair that gets packed into
+/// the i64 the host and module pass around to refer to it.
+pub struct WasmAllocation {
+    offset: Offset,
+    length: Length,
+}
+
+impl WasmAllocation {
+    /// Build an allocation at `offset` for `length` bytes. Fails if `length` is zero, since
+    /// that encoding is reserved for the return/error code convention.
+    pub fn new(offset: Offset, length: Length) -> AllocationResult {
+        if MemoryInt::from(length) == 0 {
+            return Err(AllocationError::ZeroLength);
+        }
+
+        Ok(WasmAllocation { offset, length })
+    }
+
+    pub fn offset(&self) -> Offset {
+        self.offset
+    }
+
+    pub fn length(&self) -> Length {
+        self.length
+    }
+
+    /// Largest length a single `WasmAllocation` can carry: the full width of the `MemoryBits`
+    /// channel's offset/length half.
+    pub fn max() -> MemoryBits {
+        MemoryInt::MAX as MemoryBits
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Diagnostics on a `WasmStack`'s freeing-bump allocator.
+pub struct AllocationStats {
+    bytes_in_use: usize,
+    peak_bytes_in_use: usize,
+    live_allocations: usize,
+}
+
+impl AllocationStats {
+    /// Bytes currently handed out and not yet deallocated.
+    pub fn bytes_in_use(&self) -> usize {
+        self.bytes_in_use
+    }
+
+    /// The highest `bytes_in_use` has ever reached.
+    pub fn peak_bytes_in_use(&self) -> usize {
+        self.peak_bytes_in_use
+    }
+
+    /// Number of allocations currently live.
+    pub fn live_allocations(&self) -> usize {
+        self.live_allocations
+    }
+
+    pub(crate) fn record_alloc(&mut self, block_size: usize) {
+        self.bytes_in_use += block_size;
+        self.live_allocations += 1;
+        if self.bytes_in_use > self.peak_bytes_in_use {
+            self.peak_bytes_in_use = self.bytes_in_use;
+        }
+    }
+
+    pub(crate) fn record_dealloc(&mut self, block_size: usize) {
+        self.bytes_in_use -= block_size;
+        self.live_allocations -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wasm32_ceiling_matches_the_full_width_memory_int_can_represent() {
+        assert_eq!(WASM32_MAX_BYTES, WasmAllocation::max());
+    }
+}
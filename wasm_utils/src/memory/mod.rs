@@ -0,0 +1,4 @@
+pub mod allocation;
+pub mod stack;
+
+pub use self::allocation::{MemoryBits, MemoryInt};
@@ -0,0 +1,280 @@
+//! A freeing-bump allocator over a wasm linear memory's address space: allocations are
+//! rounded up to a power-of-two size class ("order"), a free-list per order lets a freed
+//! block be handed straight back out, and only a size class with nothing free falls back to
+//! bumping a "top" pointer. The highest address this stack may ever bump into (`max`) lets a
+//! caller cap how much of a shared memory one stack is allowed to claim.
+
+use std::collections::HashMap;
+
+use crate::memory::allocation::{
+    AllocationError, AllocationResult, AllocationStats, Length, MemoryInt, Offset, WasmAllocation,
+};
+
+/// Smallest block the allocator ever hands out, and the number of bytes every block reserves
+/// on top of its requested length. The block headers themselves (order and free-list link)
+/// are kept out of line in `WasmStack::headers`, not inside the block.
+const MIN_BLOCK_ORDER: usize = 3; // 2^3 == 8 bytes
+const HEADER_SIZE: MemoryInt = 8;
+/// Orders run from `MIN_BLOCK_ORDER` up to the width of `MemoryInt` itself; nothing can be
+/// larger than the whole address space a single order could represent.
+const ORDER_COUNT: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Whether a block is currently handed out or sitting on its order's free list, carried
+/// explicitly rather than inferred from `next_free: None` so a second `deallocate` of an
+/// already-free block can be rejected instead of corrupting the free list.
+enum BlockState {
+    Occupied,
+    Free { next_free: Option<MemoryInt> },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BlockHeader {
+    order: u8,
+    state: BlockState,
+}
+
+#[derive(Clone, Debug)]
+pub struct WasmStack {
+    /// Address of the first byte that has never been handed out, either fresh or as a block.
+    top: MemoryInt,
+    /// Highest address this stack may ever bump into; `allocate` fails with `OutOfBounds`
+    /// rather than cross it.
+    max: MemoryInt,
+    /// Free-list head per order, `None` when that order has nothing to reuse.
+    free_lists: [Option<MemoryInt>; ORDER_COUNT],
+    /// Header for every block this stack has ever bumped past, keyed by the block's offset.
+    headers: HashMap<MemoryInt, BlockHeader>,
+    stats: AllocationStats,
+}
+
+impl Default for WasmStack {
+    fn default() -> Self {
+        WasmStack::with_max(MemoryInt::MAX)
+    }
+}
+
+impl WasmStack {
+    /// Build a stack whose bump pointer may never cross `max`. Used to cap one arena of a
+    /// `MemoryManager` to its own bucket of a shared linear memory.
+    pub fn with_max(max: MemoryInt) -> Self {
+        WasmStack {
+            top: 0,
+            max,
+            free_lists: [None; ORDER_COUNT],
+            headers: HashMap::new(),
+            stats: AllocationStats::default(),
+        }
+    }
+
+    fn order_for(length: Length) -> Result<usize, AllocationError> {
+        // Widen to u64 before adding the header: MemoryInt::MAX + HEADER_SIZE always fits, so
+        // this can never overflow the way a same-width addition could.
+        let needed = u64::from(MemoryInt::from(length)) + u64::from(HEADER_SIZE);
+        // ceil(log2(needed)), since `needed` is always >= HEADER_SIZE this never underflows.
+        let bits_needed = 64 - (needed - 1).leading_zeros() as usize;
+        let order = bits_needed.max(MIN_BLOCK_ORDER);
+
+        if order >= ORDER_COUNT {
+            return Err(AllocationError::OutOfBounds);
+        }
+
+        Ok(order)
+    }
+
+    fn block_size(order: usize) -> MemoryInt {
+        1 << order
+    }
+
+    /// Preview the allocation `allocate` would make for `length`, without committing it: the
+    /// head of that order's free list if one is available to reuse, otherwise wherever the
+    /// bump pointer currently sits.
+    pub fn next_allocation(&self, length: Length) -> AllocationResult {
+        let order = Self::order_for(length)?;
+        let offset = self.free_lists[order].unwrap_or(self.top);
+        WasmAllocation::new(Offset::from(offset), length)
+    }
+
+    /// What committing `allocation` would do to the bump pointer, without mutating `self` or
+    /// touching the free lists. Lets a caller validate (and grow backing memory) before the
+    /// allocation is actually recorded.
+    pub fn peek_top(&self, allocation: WasmAllocation) -> Result<MemoryInt, AllocationError> {
+        let order = Self::order_for(allocation.length())?;
+        let offset = MemoryInt::from(allocation.offset());
+
+        if self.free_lists[order] == Some(offset) {
+            return Ok(self.top);
+        }
+
+        let new_top = offset
+            .checked_add(Self::block_size(order))
+            .ok_or(AllocationError::OutOfBounds)?;
+
+        if new_top > self.max {
+            return Err(AllocationError::OutOfBounds);
+        }
+
+        Ok(new_top)
+    }
+
+    /// Commit an allocation `next_allocation` previously proposed: pop it off its order's free
+    /// list if it was a reuse, or bump `top` past a freshly carved-out block otherwise.
+    /// Returns the new bump pointer so callers can grow backing memory to cover it.
+    pub fn allocate(&mut self, allocation: WasmAllocation) -> Result<MemoryInt, AllocationError> {
+        let new_top = self.peek_top(allocation)?;
+        let order = Self::order_for(allocation.length())?;
+        let offset = MemoryInt::from(allocation.offset());
+
+        if self.free_lists[order] == Some(offset) {
+            let next_free = self.headers.get(&offset).and_then(|header| match header.state {
+                BlockState::Free { next_free } => next_free,
+                BlockState::Occupied => None,
+            });
+            self.free_lists[order] = next_free;
+        } else {
+            self.top = new_top;
+        }
+
+        self.headers.insert(
+            offset,
+            BlockHeader {
+                order: order as u8,
+                state: BlockState::Occupied,
+            },
+        );
+        self.stats.record_alloc(Self::block_size(order) as usize);
+
+        Ok(self.top)
+    }
+
+    /// Free a previous allocation: recover its order from the header left behind and push the
+    /// block onto that order's free list for a later `allocate` to reuse. Rejects a block that
+    /// is already free instead of re-linking it, which would otherwise corrupt the free list
+    /// and let two live allocations alias the same offset.
+    pub fn deallocate(&mut self, allocation: WasmAllocation) -> Result<(), AllocationError> {
+        let offset = MemoryInt::from(allocation.offset());
+        let header = *self.headers.get(&offset).ok_or(AllocationError::BadInput)?;
+
+        if header.state != BlockState::Occupied {
+            return Err(AllocationError::BadInput);
+        }
+
+        let order = header.order as usize;
+
+        self.headers.insert(
+            offset,
+            BlockHeader {
+                order: header.order,
+                state: BlockState::Free {
+                    next_free: self.free_lists[order],
+                },
+            },
+        );
+        self.free_lists[order] = Some(offset);
+        self.stats.record_dealloc(Self::block_size(order) as usize);
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> AllocationStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bumps_top_past_each_allocation() {
+        let mut stack = WasmStack::default();
+
+        let first = stack.next_allocation(Length::from(10)).unwrap();
+        assert_eq!(MemoryInt::from(first.offset()), 0);
+        stack.allocate(first).unwrap();
+
+        let second = stack.next_allocation(Length::from(5)).unwrap();
+        assert_ne!(MemoryInt::from(second.offset()), MemoryInt::from(first.offset()));
+    }
+
+    #[test]
+    fn refuses_to_bump_past_its_max() {
+        let mut stack = WasmStack::with_max(16);
+
+        let first = stack.next_allocation(Length::from(4)).unwrap();
+        stack.allocate(first).unwrap();
+
+        // The block this would carve out starts at `max` (16) and ends past it, so it must fail rather than
+        // silently bump into whatever sits beyond this stack's bucket.
+        let second = stack.next_allocation(Length::from(4)).unwrap();
+        assert_eq!(stack.allocate(second), Err(AllocationError::OutOfBounds));
+    }
+
+    #[test]
+    fn deallocate_frees_a_block_for_reuse_without_moving_top() {
+        let mut stack = WasmStack::default();
+
+        let first = stack.next_allocation(Length::from(10)).unwrap();
+        let top_after_first = stack.allocate(first).unwrap();
+        assert_eq!(stack.stats().live_allocations(), 1);
+
+        stack.deallocate(first).unwrap();
+        assert_eq!(stack.stats().live_allocations(), 0);
+
+        // A same-order allocation right after a deallocate reuses the freed block instead of
+        // bumping `top` further.
+        let second = stack.next_allocation(Length::from(10)).unwrap();
+        assert_eq!(second.offset(), first.offset());
+        let top_after_second = stack.allocate(second).unwrap();
+        assert_eq!(top_after_second, top_after_first);
+        assert_eq!(stack.stats().live_allocations(), 1);
+    }
+
+    #[test]
+    fn tracks_peak_and_live_allocation_stats() {
+        let mut stack = WasmStack::default();
+
+        let first = stack.next_allocation(Length::from(10)).unwrap();
+        stack.allocate(first).unwrap();
+        let second = stack.next_allocation(Length::from(10)).unwrap();
+        stack.allocate(second).unwrap();
+
+        assert_eq!(stack.stats().live_allocations(), 2);
+        let peak = stack.stats().peak_bytes_in_use();
+
+        stack.deallocate(first).unwrap();
+        assert_eq!(stack.stats().live_allocations(), 1);
+        // Freeing never reduces the recorded peak.
+        assert_eq!(stack.stats().peak_bytes_in_use(), peak);
+    }
+
+    #[test]
+    fn an_allocation_too_large_for_any_order_is_out_of_bounds() {
+        let stack = WasmStack::default();
+        let huge = Length::from(MemoryInt::MAX);
+        assert_eq!(stack.next_allocation(huge), Err(AllocationError::OutOfBounds));
+    }
+
+    #[test]
+    fn deallocating_an_already_free_block_is_rejected_instead_of_corrupting_the_free_list() {
+        let mut stack = WasmStack::default();
+
+        let first = stack.next_allocation(Length::from(10)).unwrap();
+        stack.allocate(first).unwrap();
+        stack.deallocate(first).unwrap();
+
+        // A second deallocate of the same, already-free block must be rejected rather than
+        // re-linking it onto the free list a second time.
+        assert_eq!(stack.deallocate(first), Err(AllocationError::BadInput));
+
+        // The free list must still be sound: only one allocation comes back out for the one
+        // live free block, and a further allocation has to bump `top` for a second, distinct
+        // offset rather than aliasing the first.
+        let reused = stack.next_allocation(Length::from(10)).unwrap();
+        assert_eq!(reused.offset(), first.offset());
+        stack.allocate(reused).unwrap();
+
+        let third = stack.next_allocation(Length::from(10)).unwrap();
+        assert_ne!(third.offset(), first.offset());
+    }
+}